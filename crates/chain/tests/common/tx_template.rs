@@ -1,10 +1,10 @@
 use rand::distributions::{Alphanumeric, DistString};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
-use bdk_chain::{tx_graph::TxGraph, BlockId, SpkTxOutIndex};
+use bdk_chain::{tx_graph::TxGraph, BlockId, ChainOracle, SpkTxOutIndex};
 use bitcoin::{
-    locktime::absolute::LockTime, secp256k1::Secp256k1, OutPoint, ScriptBuf, Sequence, Transaction,
-    TxIn, TxOut, Txid, Witness,
+    locktime::absolute::LockTime, secp256k1::Secp256k1, transaction, OutPoint, ScriptBuf, Sequence,
+    Transaction, TxIn, TxOut, Txid, Witness,
 };
 use miniscript::Descriptor;
 
@@ -13,27 +13,90 @@ use miniscript::Descriptor;
 /// The incentive for transaction templates is to create a transaction history in a simple manner to
 /// avoid having to explicitly hash previous transactions to form previous outpoints of later
 /// transactions.
-#[derive(Clone, Copy, Default)]
+#[derive(Clone, Copy)]
 pub struct TxTemplate<'a, A> {
     /// Uniquely identifies the transaction, before it can have a txid.
     pub tx_name: &'a str,
+    /// The transaction version, defaults to `transaction::Version::TWO`.
+    pub version: transaction::Version,
+    /// The transaction locktime, defaults to `LockTime::ZERO`.
+    pub lock_time: LockTime,
     pub inputs: &'a [TxInTemplate<'a>],
     pub outputs: &'a [TxOutTemplate],
     pub anchors: &'a [A],
     pub last_seen: Option<u64>,
+    /// The expected fee of this transaction, checked against `TxGraph::calculate_fee` once all
+    /// referenced prevouts are resolved. `None` skips the check.
+    ///
+    /// Only usable when every input is a `TxInTemplate::PrevTx` — `Coinbase` and `Bogus` inputs
+    /// have no prevout in the graph to resolve a value from, so `init_graph` will panic if
+    /// `expected_fee` is set on a template that uses them.
+    pub expected_fee: Option<u64>,
+}
+
+impl<'a, A> Default for TxTemplate<'a, A> {
+    fn default() -> Self {
+        Self {
+            tx_name: "",
+            version: transaction::Version::TWO,
+            lock_time: LockTime::ZERO,
+            inputs: &[],
+            outputs: &[],
+            anchors: &[],
+            last_seen: None,
+            expected_fee: None,
+        }
+    }
 }
 
 #[allow(dead_code)]
 pub enum TxInTemplate<'a> {
     /// This will give a random txid and vout.
-    Bogus,
+    Bogus {
+        /// Defaults to `Sequence::default()` if not provided.
+        sequence: Option<Sequence>,
+        /// Defaults to `Witness::new()` if not provided.
+        witness: Option<Witness>,
+    },
 
     /// This is used for coinbase transactions because they do not have previous outputs.
     Coinbase,
 
     /// Contains the `tx_name` and `vout` that we are spending. The rule is that we must only spend
-    /// from tx of a previous `TxTemplate`.
-    PrevTx(&'a str, usize),
+    /// from tx of a previous `TxTemplate`. Multiple templates are allowed to reference the same
+    /// `(tx_name, vout)`, which models conflicting/double-spending transactions (e.g. an RBF
+    /// replacement) for `TxGraph` to resolve.
+    PrevTx {
+        name: &'a str,
+        vout: usize,
+        /// Defaults to `Sequence::default()` if not provided.
+        sequence: Option<Sequence>,
+        /// Defaults to `Witness::new()` if not provided.
+        witness: Option<Witness>,
+    },
+}
+
+#[allow(dead_code)]
+impl<'a> TxInTemplate<'a> {
+    /// Shorthand for [`TxInTemplate::Bogus`] with default sequence/witness, for the common case
+    /// where neither needs to be templated.
+    pub fn bogus() -> Self {
+        Self::Bogus {
+            sequence: None,
+            witness: None,
+        }
+    }
+
+    /// Shorthand for [`TxInTemplate::PrevTx`] with default sequence/witness, for the common case
+    /// where neither needs to be templated.
+    pub fn spend(tx_name: &'a str, vout: usize) -> Self {
+        Self::PrevTx {
+            name: tx_name,
+            vout,
+            sequence: None,
+            witness: None,
+        }
+    }
 }
 
 pub struct TxOutTemplate {
@@ -68,13 +131,13 @@ pub fn init_graph<'a>(
 
     for (bogus_txin_vout, tx_tmp) in tx_templates.into_iter().enumerate() {
         let tx = Transaction {
-            version: 0,
-            lock_time: LockTime::ZERO,
+            version: tx_tmp.version,
+            lock_time: tx_tmp.lock_time,
             input: tx_tmp
                 .inputs
                 .iter()
                 .map(|input| match input {
-                    TxInTemplate::Bogus => TxIn {
+                    TxInTemplate::Bogus { sequence, witness } => TxIn {
                         previous_output: OutPoint::new(
                             bitcoin::hashes::Hash::hash(
                                 Alphanumeric
@@ -84,8 +147,8 @@ pub fn init_graph<'a>(
                             bogus_txin_vout as u32,
                         ),
                         script_sig: ScriptBuf::new(),
-                        sequence: Sequence::default(),
-                        witness: Witness::new(),
+                        sequence: sequence.unwrap_or_default(),
+                        witness: witness.clone().unwrap_or_default(),
                     },
                     TxInTemplate::Coinbase => TxIn {
                         previous_output: OutPoint::null(),
@@ -93,15 +156,20 @@ pub fn init_graph<'a>(
                         sequence: Sequence::MAX,
                         witness: Witness::new(),
                     },
-                    TxInTemplate::PrevTx(prev_name, prev_vout) => {
+                    TxInTemplate::PrevTx {
+                        name: prev_name,
+                        vout: prev_vout,
+                        sequence,
+                        witness,
+                    } => {
                         let prev_txid = tx_ids.get(prev_name).expect(
                             "txin template must spend from tx of template that comes before",
                         );
                         TxIn {
                             previous_output: OutPoint::new(*prev_txid, *prev_vout as _),
                             script_sig: ScriptBuf::new(),
-                            sequence: Sequence::default(),
-                            witness: Witness::new(),
+                            sequence: sequence.unwrap_or_default(),
+                            witness: witness.clone().unwrap_or_default(),
                         }
                     }
                 })
@@ -125,6 +193,35 @@ pub fn init_graph<'a>(
         tx_ids.insert(tx_tmp.tx_name, tx.txid());
         spk_index.scan(&tx);
         let _ = graph.insert_tx(tx.clone());
+
+        if let Some(expected_fee) = tx_tmp.expected_fee {
+            let input_sum: u64 = tx
+                .input
+                .iter()
+                .map(|txin| {
+                    graph
+                        .get_txout(txin.previous_output)
+                        .expect("all inputs must resolve to a known prevout to check the fee")
+                        .value
+                })
+                .sum();
+            let output_sum: u64 = tx.output.iter().map(|txout| txout.value).sum();
+            let fee = input_sum
+                .checked_sub(output_sum)
+                .expect("fee must not be negative");
+            assert_eq!(
+                fee, expected_fee,
+                "unexpected fee for tx {}",
+                tx_tmp.tx_name
+            );
+            assert_eq!(
+                graph.calculate_fee(&tx).expect("fee must be calculable"),
+                expected_fee,
+                "TxGraph::calculate_fee mismatch for tx {}",
+                tx_tmp.tx_name
+            );
+        }
+
         for anchor in tx_tmp.anchors.iter() {
             let _ = graph.insert_anchor(tx.txid(), *anchor);
         }
@@ -134,3 +231,229 @@ pub fn init_graph<'a>(
     }
     (graph, spk_index, tx_ids)
 }
+
+/// Returns the txids that `graph` considers canonical at `chain_tip`, according to `chain`.
+#[allow(dead_code)]
+pub fn canonical_txids<C: ChainOracle>(
+    graph: &TxGraph<BlockId>,
+    chain: &C,
+    chain_tip: BlockId,
+) -> HashSet<Txid> {
+    graph
+        .list_canonical_txs(chain, chain_tip)
+        .map(|canonical_tx| canonical_tx.tx_node.txid)
+        .collect()
+}
+
+/// Computes a snapshot of currently-unspent `OutPoint -> TxOut` entries among `graph`'s canonical
+/// transactions (per `chain`/`chain_tip`).
+#[allow(dead_code)]
+pub fn unspent_outpoints<C: ChainOracle>(
+    graph: &TxGraph<BlockId>,
+    chain: &C,
+    chain_tip: BlockId,
+) -> HashMap<OutPoint, TxOut> {
+    let canonical = canonical_txids(graph, chain, chain_tip);
+
+    let spent: HashSet<OutPoint> = graph
+        .full_txs()
+        .filter(|tx_node| canonical.contains(&tx_node.txid))
+        .flat_map(|tx_node| {
+            tx_node
+                .tx
+                .input
+                .iter()
+                .map(|txin| txin.previous_output)
+                .collect::<Vec<_>>()
+        })
+        .collect();
+
+    graph
+        .full_txs()
+        .filter(|tx_node| canonical.contains(&tx_node.txid))
+        .flat_map(|tx_node| {
+            let txid = tx_node.txid;
+            tx_node
+                .tx
+                .output
+                .iter()
+                .cloned()
+                .enumerate()
+                .map(move |(vout, txout)| (OutPoint::new(txid, vout as u32), txout))
+        })
+        .filter(|(outpoint, _)| !spent.contains(outpoint))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bdk_chain::local_chain::LocalChain;
+
+    fn block_id(height: u32, seed: &str) -> BlockId {
+        BlockId {
+            height,
+            hash: bitcoin::hashes::Hash::hash(seed.as_bytes()),
+        }
+    }
+
+    /// Builds a small graph where `tx_redeem` and `tx_cancel` both spend `tx0:0`: `tx_redeem` is
+    /// only ever seen in the mempool, while `tx_cancel` gets anchored into the chain.
+    fn conflicting_graph() -> (LocalChain, TxGraph<BlockId>, HashMap<&'static str, Txid>) {
+        let block0 = block_id(0, "block0");
+        let block1 = block_id(1, "block1");
+        let chain = LocalChain::from_blocks(
+            [(block0.height, block0.hash), (block1.height, block1.hash)].into(),
+        )
+        .expect("block0 is a valid genesis block");
+
+        let tx_templates = [
+            TxTemplate {
+                tx_name: "tx0",
+                inputs: &[TxInTemplate::Coinbase],
+                outputs: &[TxOutTemplate::new(10_000, None)],
+                anchors: &[block0],
+                ..Default::default()
+            },
+            TxTemplate {
+                tx_name: "tx_redeem",
+                inputs: &[TxInTemplate::spend("tx0", 0)],
+                outputs: &[TxOutTemplate::new(9_000, None)],
+                last_seen: Some(1),
+                ..Default::default()
+            },
+            TxTemplate {
+                tx_name: "tx_cancel",
+                inputs: &[TxInTemplate::spend("tx0", 0)],
+                outputs: &[TxOutTemplate::new(8_000, None)],
+                anchors: &[block1],
+                ..Default::default()
+            },
+        ];
+        let (graph, _spk_index, tx_ids) = init_graph(&tx_templates);
+        let tx_ids = ["tx0", "tx_redeem", "tx_cancel"]
+            .iter()
+            .map(|&name| (name, tx_ids[name]))
+            .collect();
+        (chain, graph, tx_ids)
+    }
+
+    #[test]
+    fn anchored_conflict_wins_over_seen_only_conflict() {
+        let (chain, graph, tx_ids) = conflicting_graph();
+        let chain_tip = chain.tip().block_id();
+
+        let canonical = canonical_txids(&graph, &chain, chain_tip);
+
+        assert!(canonical.contains(&tx_ids["tx_cancel"]));
+        assert!(!canonical.contains(&tx_ids["tx_redeem"]));
+    }
+
+    #[test]
+    fn unspent_outpoints_excludes_non_canonical_conflict() {
+        let (chain, graph, tx_ids) = conflicting_graph();
+        let chain_tip = chain.tip().block_id();
+
+        let utxos = unspent_outpoints(&graph, &chain, chain_tip);
+
+        // tx0:0 was spent by the canonical tx_cancel, so it must not show up as unspent.
+        assert!(!utxos.contains_key(&OutPoint::new(tx_ids["tx0"], 0)));
+        // tx_cancel's output is unspent and canonical.
+        assert!(utxos.contains_key(&OutPoint::new(tx_ids["tx_cancel"], 0)));
+        // tx_redeem lost the conflict, so its output must not be in the snapshot at all.
+        assert!(!utxos.contains_key(&OutPoint::new(tx_ids["tx_redeem"], 0)));
+    }
+
+    #[test]
+    fn expected_fee_matches_resolved_prevout_values() {
+        let tx_templates = [
+            TxTemplate {
+                tx_name: "tx0",
+                inputs: &[TxInTemplate::Coinbase],
+                outputs: &[TxOutTemplate::new(10_000, None)],
+                ..Default::default()
+            },
+            TxTemplate {
+                tx_name: "tx1",
+                inputs: &[TxInTemplate::spend("tx0", 0)],
+                outputs: &[TxOutTemplate::new(9_500, None)],
+                expected_fee: Some(500),
+                ..Default::default()
+            },
+        ];
+        // `init_graph` asserts `expected_fee` against `TxGraph::calculate_fee` internally; it
+        // would panic here if the 500 sat fee did not check out.
+        let _ = init_graph(&tx_templates);
+    }
+
+    #[test]
+    #[should_panic(expected = "unexpected fee")]
+    fn wrong_expected_fee_panics() {
+        let tx_templates = [
+            TxTemplate {
+                tx_name: "tx0",
+                inputs: &[TxInTemplate::Coinbase],
+                outputs: &[TxOutTemplate::new(10_000, None)],
+                ..Default::default()
+            },
+            TxTemplate {
+                tx_name: "tx1",
+                inputs: &[TxInTemplate::spend("tx0", 0)],
+                outputs: &[TxOutTemplate::new(9_500, None)],
+                expected_fee: Some(100),
+                ..Default::default()
+            },
+        ];
+        let _ = init_graph(&tx_templates);
+    }
+
+    #[test]
+    fn version_and_lock_time_are_applied_to_the_built_transaction() {
+        let tx_templates = [TxTemplate {
+            tx_name: "tx0",
+            version: transaction::Version::ONE,
+            lock_time: LockTime::from_height(100).unwrap(),
+            inputs: &[TxInTemplate::Coinbase],
+            outputs: &[TxOutTemplate::new(10_000, None)],
+            ..Default::default()
+        }];
+        let (graph, _spk_index, tx_ids) = init_graph(&tx_templates);
+        let tx = graph
+            .get_tx(tx_ids["tx0"])
+            .expect("tx0 must be in the graph");
+
+        assert_eq!(tx.version, transaction::Version::ONE);
+        assert_eq!(tx.lock_time, LockTime::from_height(100).unwrap());
+    }
+
+    #[test]
+    fn sequence_and_witness_round_trip_onto_built_txin() {
+        let witness = Witness::from_slice(&[vec![0xab; 3]]);
+        let tx_templates = [
+            TxTemplate {
+                tx_name: "tx0",
+                inputs: &[TxInTemplate::Coinbase],
+                outputs: &[TxOutTemplate::new(10_000, None)],
+                ..Default::default()
+            },
+            TxTemplate {
+                tx_name: "tx1",
+                inputs: &[TxInTemplate::PrevTx {
+                    name: "tx0",
+                    vout: 0,
+                    sequence: Some(Sequence::from_height(1)),
+                    witness: Some(witness.clone()),
+                }],
+                outputs: &[TxOutTemplate::new(9_000, None)],
+                ..Default::default()
+            },
+        ];
+        let (graph, _spk_index, tx_ids) = init_graph(&tx_templates);
+        let tx1 = graph
+            .get_tx(tx_ids["tx1"])
+            .expect("tx1 must be in the graph");
+
+        assert_eq!(tx1.input[0].sequence, Sequence::from_height(1));
+        assert_eq!(tx1.input[0].witness, witness);
+    }
+}